@@ -0,0 +1,95 @@
+use std::default::Default;
+
+use time::Duration;
+
+/// A struct specifying the runtime configuration of a pool.
+pub struct Config {
+    /// The number of connections managed by the pool when it is created.
+    ///
+    /// Defaults to 3.
+    pub initial_size: uint,
+    /// The maximum number of connections managed by the pool.
+    ///
+    /// Defaults to 10.
+    pub max_size: uint,
+    /// The number of connections requested at a time when the pool is empty.
+    ///
+    /// Defaults to 3.
+    pub acquire_increment: uint,
+    /// If true, connections will be tested when checked out of the pool.
+    ///
+    /// Defaults to true.
+    pub test_on_check_out: bool,
+    /// The number of tasks used to asynchronously create connections.
+    ///
+    /// Defaults to 3.
+    pub helper_tasks: uint,
+    /// The minimum number of idle connections the pool tries to maintain.
+    ///
+    /// Defaults to 0.
+    pub min_idle: uint,
+    /// The amount of time `get` will wait for a connection before giving up.
+    ///
+    /// Defaults to 30 seconds.
+    pub connection_timeout: Duration,
+    /// The maximum lifetime of a connection before it is reaped.
+    ///
+    /// Defaults to `None`, meaning connections never expire due to age.
+    pub max_lifetime: Option<Duration>,
+    /// The maximum time a connection may sit idle before it is reaped.
+    ///
+    /// Defaults to `None`, meaning idle connections are never reaped.
+    pub idle_timeout: Option<Duration>,
+    /// The interval at which idle connections are scanned for expiry.
+    ///
+    /// Defaults to 30 seconds.
+    pub reaper_interval: Duration,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            initial_size: 3,
+            max_size: 10,
+            acquire_increment: 3,
+            test_on_check_out: true,
+            helper_tasks: 3,
+            min_idle: 0,
+            connection_timeout: Duration::seconds(30),
+            max_lifetime: None,
+            idle_timeout: None,
+            reaper_interval: Duration::seconds(30),
+        }
+    }
+}
+
+impl Config {
+    /// Determines if the configuration is valid.
+    pub fn validate(&self) -> Result<(), &'static str> {
+        if self.initial_size > self.max_size {
+            return Err("initial_size must be less than or equal to max_size");
+        }
+
+        if self.min_idle > self.max_size {
+            return Err("min_idle must be less than or equal to max_size");
+        }
+
+        if self.connection_timeout <= Duration::zero() {
+            return Err("connection_timeout must be positive");
+        }
+
+        if self.max_lifetime.map_or(false, |lt| lt <= Duration::zero()) {
+            return Err("max_lifetime must be positive");
+        }
+
+        if self.idle_timeout.map_or(false, |it| it <= Duration::zero()) {
+            return Err("idle_timeout must be positive");
+        }
+
+        if self.reaper_interval <= Duration::zero() {
+            return Err("reaper_interval must be positive");
+        }
+
+        Ok(())
+    }
+}
@@ -3,9 +3,16 @@
 #![warn(missing_doc)]
 #![doc(html_root_url="http://www.rust-ci.org/sfackler/r2d2/doc")]
 
+extern crate time;
+
+use time::{Duration, SteadyTime};
+
 use std::comm;
 use std::cmp;
 use std::collections::{Deque, RingBuf};
+use std::finally;
+use std::io::timer;
+use std::mem;
 use std::sync::{Arc, Mutex};
 use std::fmt;
 
@@ -23,6 +30,13 @@ pub trait PoolManager<C, E>: Send+Sync {
     /// A standard implementation would check if a simple query like `SELECT 1`
     /// succeeds.
     fn is_valid(&self, conn: &C) -> bool;
+
+    /// Quickly determines if the connection is known to be broken.
+    ///
+    /// Unlike `is_valid`, this must not perform a network round trip.
+    fn has_broken(&self, _conn: &mut C) -> bool {
+        false
+    }
 }
 
 /// An error type returned if pool creation fails.
@@ -43,13 +57,66 @@ impl<E: fmt::Show> fmt::Show for NewPoolError<E> {
     }
 }
 
+/// An error type returned by `Pool::get`.
+#[deriving(PartialEq, Eq)]
+pub enum GetError<E> {
+    /// The pool timed out while waiting for an available connection.
+    GetTimeout,
+    /// The manager returned an error when creating a connection.
+    GetConnectionError(E),
+}
+
+impl<E: fmt::Show> fmt::Show for GetError<E> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            GetTimeout => write!(f, "Timed out while waiting for a connection"),
+            GetConnectionError(ref error) => write!(f, "Unable to create a connection: {}", error),
+        }
+    }
+}
+
+/// An error type returned by `Pool::add`.
+#[deriving(PartialEq, Eq)]
+pub enum AddError<C> {
+    /// The pool was already at `max_size`; the connection is handed back.
+    PoolFull(C),
+}
+
+impl<C: fmt::Show> fmt::Show for AddError<C> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            PoolFull(ref conn) => write!(f, "Pool is full; returning connection: {}", conn),
+        }
+    }
+}
+
 enum Command<C> {
     AddConnection,
     TestConnection(C),
+    ReapConnections,
+}
+
+/// An idle connection along with the timestamps used to enforce `max_lifetime`
+/// and `idle_timeout`.
+struct IdleConn<C> {
+    conn: C,
+    created: SteadyTime,
+    last_used: SteadyTime,
+}
+
+impl<C> IdleConn<C> {
+    fn new(conn: C) -> IdleConn<C> {
+        let now = SteadyTime::now();
+        IdleConn {
+            conn: conn,
+            created: now,
+            last_used: now,
+        }
+    }
 }
 
 struct PoolInternals<C, E> {
-    conns: RingBuf<C>,
+    conns: RingBuf<IdleConn<C>>,
     failed_conns: RingBuf<E>,
     num_conns: uint,
 }
@@ -82,7 +149,7 @@ impl<C: Send, E: Send, M: PoolManager<C, E>> Pool<C, E, M> {
 
         for _ in range(0, config.initial_size) {
             match manager.connect() {
-                Ok(conn) => internals.conns.push(conn),
+                Ok(conn) => internals.conns.push(IdleConn::new(conn)),
                 Err(err) => return Err(ConnectionError(err)),
             }
         }
@@ -97,10 +164,24 @@ impl<C: Send, E: Send, M: PoolManager<C, E>> Pool<C, E, M> {
         // FIXME :(
         let receiver = Arc::new(Mutex::new(receiver));
 
-        for _ in range(0, config.helper_tasks) {
+        for _ in range(0, inner.config.helper_tasks) {
             let inner = inner.clone();
             let receiver = receiver.clone();
-            spawn(proc() helper_task(receiver, inner));
+            let sender = sender.clone();
+            spawn(proc() helper_task(receiver, sender, inner));
+        }
+
+        if inner.config.max_lifetime.is_some() || inner.config.idle_timeout.is_some() {
+            let sender = sender.clone();
+            let reaper_interval = inner.config.reaper_interval;
+            spawn(proc() {
+                loop {
+                    timer::sleep(::std::time::Duration::milliseconds(reaper_interval.num_milliseconds()));
+                    if sender.send_opt(ReapConnections).is_err() {
+                        break;
+                    }
+                }
+            });
         }
 
         Ok(Pool {
@@ -110,17 +191,24 @@ impl<C: Send, E: Send, M: PoolManager<C, E>> Pool<C, E, M> {
     }
 
     /// Retrieves a connection from the pool.
-    pub fn get<'a>(&'a self) -> Result<PooledConnection<'a, C, E, M>, E> {
+    ///
+    /// Waits for at most `connection_timeout` for a connection to become
+    /// available before returning `GetTimeout`.
+    pub fn get<'a>(&'a self) -> Result<PooledConnection<'a, C, E, M>, GetError<E>> {
+        let end = SteadyTime::now() + self.inner.config.connection_timeout;
         let mut internals = self.inner.internals.lock();
 
         loop {
             match internals.conns.pop_front() {
                 Some(conn) => {
-                    if self.inner.config.test_on_check_out && !self.inner.manager.is_valid(&conn) {
+                    if self.inner.config.test_on_check_out
+                            && !self.inner.manager.is_valid(&conn.conn) {
                         internals.num_conns -= 1;
+                        self.replenish_idle(&mut *internals);
                         continue;
                     }
 
+                    self.replenish_idle(&mut *internals);
                     return Ok(PooledConnection {
                         pool: self,
                         conn: Some(conn)
@@ -128,7 +216,7 @@ impl<C: Send, E: Send, M: PoolManager<C, E>> Pool<C, E, M> {
                 }
                 None => {
                     match internals.failed_conns.pop_front() {
-                        Some(err) => return Err(err),
+                        Some(err) => return Err(GetConnectionError(err)),
                         None => {}
                     }
 
@@ -139,20 +227,67 @@ impl<C: Send, E: Send, M: PoolManager<C, E>> Pool<C, E, M> {
                         internals.num_conns += 1;
                     }
 
-                    internals.cond.wait();
+                    let remaining = end - SteadyTime::now();
+                    if remaining <= Duration::zero() {
+                        return Err(GetTimeout);
+                    }
+                    internals.cond.wait_timeout(remaining);
                 }
             }
         }
     }
 
-    fn put_back(&self, conn: C) {
+    /// Acquires a connection and passes it to the provided closure.
+    ///
+    /// The connection is returned to the pool when the closure completes, so
+    /// callers get scoped, leak-proof access without having to remember to call
+    /// `PooledConnection::replace`. The connection is returned even if `f`
+    /// fails, so a panicking closure can't trigger the `PooledConnection`
+    /// destructor's `fail!()` during unwind.
+    pub fn with<T>(&self, f: |&C| -> T) -> Result<T, GetError<E>> {
+        let mut conn = try!(self.get());
+        let result = finally::try_finally(&mut conn,
+                                           |conn| f(&**conn),
+                                           |conn| conn.return_to_pool());
+        Ok(result)
+    }
+
+    /// Adds an externally created connection to the pool.
+    ///
+    /// If the pool is already at `max_size`, the connection is returned to the
+    /// caller wrapped in `AddError::PoolFull`.
+    pub fn add(&self, conn: C) -> Result<(), AddError<C>> {
+        let mut internals = self.inner.internals.lock();
+        if internals.num_conns >= self.inner.config.max_size {
+            return Err(PoolFull(conn));
+        }
+
+        internals.conns.push(IdleConn::new(conn));
+        internals.num_conns += 1;
+        internals.cond.signal();
+        Ok(())
+    }
+
+    fn put_back(&self, mut conn: IdleConn<C>) {
         let mut internals = self.inner.internals.lock();
-        internals.conns.push(conn);
+        if self.inner.manager.has_broken(&mut conn.conn) {
+            internals.num_conns -= 1;
+            self.replenish_idle(&mut *internals);
+        } else {
+            conn.last_used = SteadyTime::now();
+            internals.conns.push(conn);
+        }
         internals.cond.signal();
     }
+
+    /// Tops the idle queue back up to `min_idle`.
+    fn replenish_idle(&self, internals: &mut PoolInternals<C, E>) {
+        replenish_idle(&self.helper_chan, &self.inner.config, internals);
+    }
 }
 
 fn helper_task<C: Send, E: Send, M: PoolManager<C, E>>(receiver: Arc<Mutex<Receiver<Command<C>>>>,
+                                                       sender: Sender<Command<C>>,
                                                        inner: Arc<InnerPool<C, E, M>>) {
     loop {
         let mut receiver = receiver.lock();
@@ -162,17 +297,33 @@ fn helper_task<C: Send, E: Send, M: PoolManager<C, E>>(receiver: Arc<Mutex<Recei
         match res {
             Ok(AddConnection) => add_connection(&*inner),
             Ok(TestConnection(conn)) => test_connection(&*inner, conn),
+            Ok(ReapConnections) => reap_connections(&sender, &*inner),
             Err(()) => break,
         }
     }
 }
 
+/// Tops the idle queue back up to `min_idle`.
+fn replenish_idle<C: Send, E: Send>(sender: &Sender<Command<C>>, config: &Config,
+                                    internals: &mut PoolInternals<C, E>) {
+    if internals.conns.len() >= config.min_idle {
+        return;
+    }
+
+    let wanted = config.min_idle - internals.conns.len();
+    let new_conns = cmp::min(config.max_size - internals.num_conns, wanted);
+    for _ in range(0, new_conns) {
+        sender.send(AddConnection);
+        internals.num_conns += 1;
+    }
+}
+
 fn add_connection<C: Send, E: Send, M: PoolManager<C, E>>(inner: &InnerPool<C, E, M>) {
     let res = inner.manager.connect();
     let mut internals = inner.internals.lock();
     match res {
         Ok(conn) => {
-            internals.conns.push(conn);
+            internals.conns.push(IdleConn::new(conn));
         }
         Err(err) => {
             internals.failed_conns.push(err);
@@ -186,12 +337,35 @@ fn test_connection<C: Send, E: Send, M: PoolManager<C, E>>(inner: &InnerPool<C,
     let is_valid = inner.manager.is_valid(&conn);
     let mut internals = inner.internals.lock();
     if is_valid {
-        internals.conns.push(conn);
+        internals.conns.push(IdleConn::new(conn));
     } else {
         internals.num_conns -= 1;
     }
 }
 
+fn reap_connections<C: Send, E: Send, M: PoolManager<C, E>>(sender: &Sender<Command<C>>,
+                                                            inner: &InnerPool<C, E, M>) {
+    let now = SteadyTime::now();
+    let mut internals = inner.internals.lock();
+
+    let conns = mem::replace(&mut internals.conns, RingBuf::new());
+    for conn in conns.move_iter() {
+        let expired = inner.config.max_lifetime.map_or(false, |lt| now - conn.created > lt)
+            || inner.config.idle_timeout.map_or(false, |it| now - conn.last_used > it);
+        if expired {
+            internals.num_conns -= 1;
+        } else {
+            internals.conns.push(conn);
+        }
+    }
+
+    // Reaping can drop the idle count below `min_idle` even when nobody is
+    // blocked in `get`, so dispatch replacements directly instead of relying
+    // on a condvar wait that may have no waiters.
+    replenish_idle(sender, &inner.config, &mut *internals);
+    internals.cond.signal();
+}
+
 /// A smart pointer wrapping an underlying connection.
 ///
 /// ## Note
@@ -202,7 +376,7 @@ fn test_connection<C: Send, E: Send, M: PoolManager<C, E>>(inner: &InnerPool<C,
 /// or the `PooledConnection`'s destructor will `fail!()`.
 pub struct PooledConnection<'a, C, E, M> {
     pool: &'a Pool<C, E, M>,
-    conn: Option<C>,
+    conn: Option<IdleConn<C>>,
 }
 
 impl<'a, C: Send, E: Send, M: PoolManager<C, E>> PooledConnection<'a, C, E, M> {
@@ -211,7 +385,17 @@ impl<'a, C: Send, E: Send, M: PoolManager<C, E>> PooledConnection<'a, C, E, M> {
     /// This must be called before the `PooledConnection` drops out of scope or
     /// its destructor will `fail!()`.
     pub fn replace(mut self) {
-        self.pool.put_back(self.conn.take_unwrap())
+        self.return_to_pool();
+    }
+
+    /// Returns the connection to its pool without consuming `self`.
+    ///
+    /// Leaves `self.conn` as `None`, so a later drop is a no-op rather than
+    /// the usual `fail!()`.
+    fn return_to_pool(&mut self) {
+        if let Some(conn) = self.conn.take() {
+            self.pool.put_back(conn);
+        }
     }
 }
 
@@ -226,6 +410,6 @@ impl<'a, C, E, M> Drop for PooledConnection<'a, C, E, M> {
 
 impl<'a, C, E, M> Deref<C> for PooledConnection<'a, C, E, M> {
     fn deref(&self) -> &C {
-        self.conn.get_ref()
+        &self.conn.get_ref().conn
     }
 }
@@ -1,7 +1,11 @@
 extern crate r2d2;
+extern crate time;
 
-use std::sync::Mutex;
+use std::io::timer;
+use std::sync::{Arc, Mutex};
 use std::default::Default;
+use std::task;
+use time::Duration;
 
 mod config;
 
@@ -41,6 +45,41 @@ impl r2d2::PoolManager<FakeConnection, ()> for NthConnectFailManager {
     }
 }
 
+struct CountingManager {
+    connects: Arc<Mutex<uint>>,
+}
+
+impl r2d2::PoolManager<FakeConnection, ()> for CountingManager {
+    fn connect(&self) -> Result<FakeConnection, ()> {
+        *self.connects.lock() += 1;
+        Ok(FakeConnection)
+    }
+
+    fn is_valid(&self, _: &FakeConnection) -> bool {
+        true
+    }
+}
+
+struct BreaksOnReturn {
+    connects: Arc<Mutex<uint>>,
+    broken: Arc<Mutex<bool>>,
+}
+
+impl r2d2::PoolManager<FakeConnection, ()> for BreaksOnReturn {
+    fn connect(&self) -> Result<FakeConnection, ()> {
+        *self.connects.lock() += 1;
+        Ok(FakeConnection)
+    }
+
+    fn is_valid(&self, _: &FakeConnection) -> bool {
+        true
+    }
+
+    fn has_broken(&self, _conn: &mut FakeConnection) -> bool {
+        *self.broken.lock()
+    }
+}
+
 #[test]
 fn test_initial_size_ok() {
     let config = r2d2::Config {
@@ -101,3 +140,229 @@ fn test_acquire_fail() {
     assert!(pool.get().is_err());
     c1.replace();
 }
+
+#[test]
+fn test_acquire_timeout() {
+    let config = r2d2::Config {
+        initial_size: 1,
+        max_size: 1,
+        connection_timeout: Duration::milliseconds(100),
+        ..Default::default()
+    };
+    let pool = r2d2::Pool::new(config, OkManager).unwrap();
+
+    let conn = pool.get().unwrap();
+    assert_eq!(pool.get().err().unwrap(), r2d2::GetTimeout);
+    conn.replace();
+}
+
+#[test]
+fn test_with() {
+    let config = r2d2::Config {
+        initial_size: 1,
+        max_size: 1,
+        connection_timeout: Duration::milliseconds(100),
+        ..Default::default()
+    };
+    let pool = r2d2::Pool::new(config, OkManager).unwrap();
+    let result = pool.with(|conn| *conn == FakeConnection).unwrap();
+    assert!(result);
+
+    // the connection must have been returned to the pool; with `max_size` at
+    // 1, a leaked connection here would make this time out
+    pool.get().unwrap().replace();
+}
+
+#[test]
+fn test_with_panic_safe() {
+    let config = r2d2::Config {
+        initial_size: 1,
+        max_size: 1,
+        connection_timeout: Duration::milliseconds(100),
+        ..Default::default()
+    };
+    let pool = Arc::new(r2d2::Pool::new(config, OkManager).unwrap());
+
+    let guard = pool.clone();
+    let result = task::try(proc() {
+        guard.with(|_: &FakeConnection| -> () { fail!("boom") }).ok();
+    });
+    assert!(result.is_err());
+
+    // the connection must still have been returned to the pool despite the
+    // closure panicking; with `max_size` at 1, a leaked connection here would
+    // make this time out instead of succeeding
+    pool.get().unwrap().replace();
+}
+
+#[test]
+fn test_config_min_idle_err() {
+    let config = r2d2::Config {
+        min_idle: 11,
+        max_size: 10,
+        ..Default::default()
+    };
+    assert!(config.validate().is_err());
+}
+
+#[test]
+fn test_min_idle_replenish() {
+    let config = r2d2::Config {
+        initial_size: 1,
+        max_size: 3,
+        min_idle: 2,
+        ..Default::default()
+    };
+    let connects = Arc::new(Mutex::new(0u));
+    let manager = CountingManager { connects: connects.clone() };
+    let pool = r2d2::Pool::new(config, manager).unwrap();
+    assert_eq!(*connects.lock(), 1);
+
+    // checking the only idle connection back out drops the idle count to
+    // zero, which should dispatch enough `AddConnection` commands to bring
+    // it back up to `min_idle` without any caller having to ask
+    let conn = pool.get().unwrap();
+    conn.replace();
+
+    let mut replenished = false;
+    for _ in range(0u, 100) {
+        if *connects.lock() >= 3 {
+            replenished = true;
+            break;
+        }
+        timer::sleep(::std::time::Duration::milliseconds(10));
+    }
+    assert!(replenished, "min_idle was not replenished in the background");
+}
+
+#[test]
+fn test_has_broken_evicts_connection() {
+    let config = r2d2::Config {
+        initial_size: 1,
+        max_size: 1,
+        ..Default::default()
+    };
+    let connects = Arc::new(Mutex::new(0u));
+    let broken = Arc::new(Mutex::new(false));
+    let manager = BreaksOnReturn { connects: connects.clone(), broken: broken.clone() };
+    let pool = r2d2::Pool::new(config, manager).unwrap();
+    assert_eq!(*connects.lock(), 1);
+
+    let conn = pool.get().unwrap();
+    *broken.lock() = true;
+    conn.replace();
+    *broken.lock() = false;
+
+    // the broken connection must have been discarded rather than re-queued,
+    // and `num_conns` must no longer count it: a later `get` still
+    // succeeds, backed by a freshly connected replacement
+    pool.get().unwrap().replace();
+    assert_eq!(*connects.lock(), 2);
+}
+
+#[test]
+fn test_add_ok() {
+    let config = r2d2::Config {
+        initial_size: 0,
+        max_size: 1,
+        ..Default::default()
+    };
+    let pool = r2d2::Pool::new(config, OkManager).unwrap();
+
+    assert!(pool.add(FakeConnection).is_ok());
+    let conn = pool.get().unwrap();
+    conn.replace();
+}
+
+#[test]
+fn test_add_full() {
+    let config = r2d2::Config {
+        initial_size: 1,
+        max_size: 1,
+        ..Default::default()
+    };
+    let pool = r2d2::Pool::new(config, OkManager).unwrap();
+
+    match pool.add(FakeConnection) {
+        Err(r2d2::PoolFull(FakeConnection)) => {}
+        _ => fail!("expected PoolFull"),
+    }
+}
+
+#[test]
+fn test_config_max_lifetime_err() {
+    let config = r2d2::Config {
+        max_lifetime: Some(Duration::seconds(0)),
+        ..Default::default()
+    };
+    assert!(config.validate().is_err());
+}
+
+#[test]
+fn test_config_idle_timeout_err() {
+    let config = r2d2::Config {
+        idle_timeout: Some(Duration::seconds(0)),
+        ..Default::default()
+    };
+    assert!(config.validate().is_err());
+}
+
+#[test]
+fn test_max_lifetime_reaps() {
+    let config = r2d2::Config {
+        initial_size: 1,
+        max_size: 1,
+        min_idle: 1,
+        max_lifetime: Some(Duration::milliseconds(50)),
+        reaper_interval: Duration::milliseconds(20),
+        ..Default::default()
+    };
+    let connects = Arc::new(Mutex::new(0u));
+    let manager = CountingManager { connects: connects.clone() };
+    let pool = r2d2::Pool::new(config, manager).unwrap();
+    assert_eq!(*connects.lock(), 1);
+
+    // once the connection exceeds `max_lifetime` the reaper should drop it
+    // and, with `min_idle` set, immediately dispatch a replacement
+    let mut reaped = false;
+    for _ in range(0u, 100) {
+        if *connects.lock() >= 2 {
+            reaped = true;
+            break;
+        }
+        timer::sleep(::std::time::Duration::milliseconds(10));
+    }
+    assert!(reaped, "stale connection was not reaped and replaced");
+
+    pool.get().unwrap().replace();
+}
+
+#[test]
+fn test_idle_timeout_reaps() {
+    let config = r2d2::Config {
+        initial_size: 1,
+        max_size: 1,
+        min_idle: 1,
+        idle_timeout: Some(Duration::milliseconds(50)),
+        reaper_interval: Duration::milliseconds(20),
+        ..Default::default()
+    };
+    let connects = Arc::new(Mutex::new(0u));
+    let manager = CountingManager { connects: connects.clone() };
+    let pool = r2d2::Pool::new(config, manager).unwrap();
+    assert_eq!(*connects.lock(), 1);
+
+    // once the connection sits idle past `idle_timeout` the reaper should
+    // drop it and, with `min_idle` set, immediately dispatch a replacement
+    let mut reaped = false;
+    for _ in range(0u, 100) {
+        if *connects.lock() >= 2 {
+            reaped = true;
+            break;
+        }
+        timer::sleep(::std::time::Duration::milliseconds(10));
+    }
+    assert!(reaped, "idle connection was not reaped and replaced");
+
+    pool.get().unwrap().replace();
+}